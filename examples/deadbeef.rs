@@ -1,5 +1,5 @@
 use std::io::Error;
-use vblk::{mount, BlockDevice};
+use vblk::{default_error_map, mount, BlockDevice};
 
 struct DeadbeefDevice;
 
@@ -30,7 +30,7 @@ impl BlockDevice for DeadbeefDevice {
 
 fn main() {
     unsafe {
-        mount(&mut DeadbeefDevice, "/dev/nbd0", |device| {
+        mount(&mut DeadbeefDevice, "/dev/nbd0", 1, default_error_map, |device| {
             ctrlc::set_handler(move || {
                 device.unmount().unwrap();
             })
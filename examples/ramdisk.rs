@@ -1,5 +1,5 @@
 use std::io::Result;
-use vblk::{mount, BlockDevice};
+use vblk::{default_error_map, mount, BlockDevice};
 
 struct RamDisk {
     memory: Vec<u8>,
@@ -14,7 +14,7 @@ impl BlockDevice for RamDisk {
         Ok(())
     }
 
-    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<()> {
+    fn write(&mut self, offset: u64, bytes: &[u8], _fua: bool) -> Result<()> {
         println!("write request offset {} len {}", offset, bytes.len());
 
         self.memory[offset as usize..offset as usize + bytes.len()].copy_from_slice(bytes);
@@ -49,7 +49,7 @@ fn main() {
     };
 
     unsafe {
-        mount(&mut disk, "/dev/nbd0", |device| {
+        mount(&mut disk, "/dev/nbd0", 1, default_error_map, |device| {
             ctrlc::set_handler(move || {
                 device.unmount().unwrap();
             })
@@ -0,0 +1,376 @@
+//! Asynchronous block device support.
+//!
+//! This mirrors the synchronous [`BlockDevice`](crate::BlockDevice) trait and
+//! [`mount`](crate::mount) entry point, but lets the request loop keep several
+//! requests outstanding at once instead of blocking on each one in turn. Slow
+//! reads (network-backed or compressed stores, say) therefore no longer stall
+//! unrelated requests sharing the same socket.
+
+use crate::{default_error_map, nbd};
+use async_trait::async_trait;
+use nix::errno::Errno::{EINVAL, EPERM};
+use std::fs::OpenOptions;
+use std::io::{Error, Result};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::UnixStream;
+use tokio::sync::Mutex;
+use zerocopy::AsBytes;
+
+/// A virtual block device driven asynchronously.
+///
+/// Each method returns a future, so the dispatcher can await several of them
+/// concurrently. Because requests may be in flight simultaneously the methods
+/// take `&self`; use interior mutability if the device needs to mutate state.
+#[async_trait]
+pub trait AsyncBlockDevice: Send + Sync {
+    /// Reads `len` bytes starting at `offset` from the block device.
+    ///
+    /// # Note
+    ///
+    /// If you return an I/O error not associated with an OS `errno`, vblk
+    /// will automatically return an `EIO` error to the caller by default.
+    async fn read(&self, offset: u64, len: u32) -> Result<Vec<u8>> {
+        let _ = (offset, len);
+
+        Err(Error::from_raw_os_error(EPERM as i32))
+    }
+
+    /// Writes a byte range to the block device.
+    ///
+    /// When `fua` (force unit access) is set the kernel requires this single
+    /// write to be durably committed before the reply is sent, so durability-
+    /// sensitive devices should flush it synchronously rather than relying on a
+    /// later [`flush`](AsyncBlockDevice::flush).
+    ///
+    /// # Note
+    ///
+    /// If you return an I/O error not associated with an OS `errno`, vblk
+    /// will automatically return an `EIO` error to the caller by default.
+    async fn write(&self, offset: u64, bytes: &[u8], fua: bool) -> Result<()> {
+        let _ = (offset, bytes, fua);
+
+        Err(Error::from_raw_os_error(EPERM as i32))
+    }
+
+    /// Flushes any cached data to the block device.
+    ///
+    /// # Warning
+    ///
+    /// Support for this command depends on your Linux kernel version.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Trims a byte range of the block device.
+    ///
+    /// # Warning
+    ///
+    /// Support for this command depends on your Linux kernel version.
+    async fn trim(&self, offset: u64, len: u32) -> Result<()> {
+        let _ = (offset, len);
+
+        Ok(())
+    }
+
+    /// Writes a range of zeroes to the block device.
+    ///
+    /// The default implementation simply writes a zero-filled buffer, but
+    /// devices backed by sparse files or allocation bitmaps can override this
+    /// to punch a hole instead of materializing the zeroes.
+    ///
+    /// The `fua` flag carries the same force-unit-access meaning as for
+    /// [`write`](AsyncBlockDevice::write).
+    ///
+    /// # Warning
+    ///
+    /// Support for this command depends on your Linux kernel version.
+    async fn write_zeroes(&self, offset: u64, len: u32, fua: bool) -> Result<()> {
+        self.write(offset, &vec![0; len as usize], fua).await
+    }
+
+    /// Called when the block device is unmounted.
+    async fn unmount(&self) {}
+
+    /// Returns whether the device should be mounted read-only.
+    ///
+    /// When this returns `true` the `NBD_FLAG_READ_ONLY` flag is advertised so
+    /// the kernel rejects writes at the block layer, and vblk additionally
+    /// refuses write, trim and write-zeroes commands with `EPERM` as a defense
+    /// in depth. This is useful for exposing snapshot or archival images where
+    /// accidental writes must be impossible.
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Returns the device block size in bytes.
+    ///
+    /// According to the NBD kernel source code, the block size must currently
+    /// be a power of two between 512 bytes and the system page size in bytes.
+    fn block_size(&self) -> u32;
+
+    /// Returns the device size in blocks.
+    fn blocks(&self) -> u64;
+}
+
+/// The write half of the userspace socket, shared behind a mutex so that reply
+/// headers and payloads from concurrent requests never interleave on the wire.
+type SharedWriter = Arc<Mutex<OwnedWriteHalf>>;
+
+/// Mounts an asynchronous block device on an NBD device such as `/dev/nbd0`.
+///
+/// Unlike [`mount`](crate::mount) this keeps multiple requests outstanding at
+/// once: the dispatcher reads a request header, spawns the device future, and
+/// writes the tagged [`Reply`](crate::nbd::Reply) when that future resolves.
+/// Replies may therefore be returned out of order, so only the socket writes
+/// are serialized (behind a mutex) to avoid interleaving reply payloads.
+///
+/// # Safety
+///
+/// Communicates with the NBD kernel module through ioctls.
+pub async unsafe fn mount_async<P: AsRef<Path>>(
+    device: Arc<dyn AsyncBlockDevice>,
+    path: P,
+) -> Result<()> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path.as_ref())?;
+
+    let (block_size, blocks) = (device.block_size(), device.blocks());
+    let read_only = device.read_only();
+
+    assert!(block_size.is_power_of_two());
+    assert!(block_size >= 512);
+
+    nbd::set_blksize(&file, block_size)?;
+    nbd::set_size_blocks(&file, blocks)?;
+    nbd::clear_sock(&file)?;
+
+    let (userspace_socket, kernel_socket) = std::os::unix::net::UnixStream::pair()?;
+
+    // The NBD ioctls block the calling thread for the lifetime of the device,
+    // so drive them on a dedicated blocking thread while the async dispatcher
+    // services requests on the userspace end of the socket pair.
+
+    let kernel_fd = kernel_socket.as_raw_fd();
+    let driver_file = file.try_clone()?;
+
+    let driver = tokio::task::spawn_blocking(move || -> Result<()> {
+        let _kernel_socket = kernel_socket;
+
+        nbd::set_sock(&driver_file, kernel_fd)?;
+
+        // These flags (or even the ability to set flags) are not available
+        // in every Linux version; this call is best-effort, ignore errors.
+
+        let mut flags = nbd::SEND_FLUSH | nbd::SEND_FUA | nbd::SEND_TRIM | nbd::SEND_WRITE_ZEROES;
+
+        if read_only {
+            flags |= nbd::READ_ONLY;
+        }
+
+        let _ = nbd::set_flags(&driver_file, flags);
+
+        nbd::do_it(&driver_file)?;
+
+        // We can't really do anything meaningful if these cleanup calls
+        // fail, so just assume that they succeed and hope for the best.
+
+        let _ = nbd::clear_sock(&driver_file);
+        let _ = nbd::clear_que(&driver_file);
+
+        Ok(())
+    });
+
+    userspace_socket.set_nonblocking(true)?;
+    let (reader, writer) = UnixStream::from_std(userspace_socket)?.into_split();
+    let writer: SharedWriter = Arc::new(Mutex::new(writer));
+
+    let result = dispatch(reader, &writer, &device).await;
+
+    drop(writer);
+
+    if result.is_err() {
+        let _ = nbd::disconnect(&file); // forced disconnect
+    }
+
+    let driver = driver.await.expect("NBD driver thread panicked");
+
+    result.and(driver)
+}
+
+/// Reads requests off the socket and services each one concurrently, tagging
+/// every [`Reply`](crate::nbd::Reply) by the request's 64-bit `handle`.
+async fn dispatch(
+    mut reader: OwnedReadHalf,
+    writer: &SharedWriter,
+    device: &Arc<dyn AsyncBlockDevice>,
+) -> Result<()> {
+    let read_only = device.read_only();
+    let device_size = device.blocks() * u64::from(device.block_size());
+
+    // A request is in bounds if it stays within the cap and the device size;
+    // the subtraction below cannot underflow because the offset is checked
+    // against the size first.
+
+    let out_of_range = |offset: u64, len: u32| {
+        len > crate::MAX_REQUEST_LEN
+            || offset > device_size
+            || u64::from(len) > device_size - offset
+    };
+
+    // Track the in-flight request tasks so a failed socket write (broken pipe,
+    // kernel gone) is surfaced instead of silently swallowed; an error here
+    // tears the dispatcher down and forces a disconnect in `mount_async`.
+
+    let mut tasks = tokio::task::JoinSet::new();
+
+    loop {
+        while let Some(joined) = tasks.try_join_next() {
+            joined.expect("request task panicked")?;
+        }
+
+        let mut request = nbd::Request::default();
+
+        match reader
+            .read_exact(&mut request.as_bytes_mut()[0..nbd::REQUEST_LEN])
+            .await
+        {
+            Ok(_) => {}
+            Err(ref err) if err.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+
+        assert!(request.is_magic_valid(), "NBD driver error: invalid magic");
+
+        let (offset, length) = (request.offset(), request.len());
+        let fua = request.flags() & nbd::FLAG_FUA != 0;
+        let oor = out_of_range(offset, length);
+        let mut reply = request.new_reply_for_request();
+
+        match request.command() {
+            nbd::Command::Read => {
+                if oor {
+                    reply.set_errno(EINVAL as i32);
+                    writer.lock().await.write_all(reply.as_bytes()).await?;
+                    continue;
+                }
+
+                let device = Arc::clone(device);
+                let writer = Arc::clone(writer);
+
+                tasks.spawn(async move {
+                    // On error the payload is undefined, so send only the
+                    // failure reply and skip it, as clients expect.
+
+                    let payload = match device.read(offset, length).await {
+                        Ok(payload) => Some(payload),
+                        Err(err) => {
+                            reply.set_errno(default_error_map(&err));
+                            None
+                        }
+                    };
+
+                    let mut writer = writer.lock().await;
+                    writer.write_all(reply.as_bytes()).await?;
+
+                    if let Some(payload) = payload {
+                        writer.write_all(&payload).await?;
+                    }
+
+                    Ok::<_, Error>(())
+                });
+            }
+            nbd::Command::Write => {
+                // An oversized payload cannot be drained safely, so reject it
+                // and stop the dispatcher rather than read an unbounded amount.
+
+                if length > crate::MAX_REQUEST_LEN {
+                    reply.set_errno(EINVAL as i32);
+                    writer.lock().await.write_all(reply.as_bytes()).await?;
+                    break;
+                }
+
+                let mut buffer = vec![0; length as usize];
+                reader.read_exact(&mut buffer).await?;
+
+                let device = Arc::clone(device);
+                let writer = Arc::clone(writer);
+
+                tasks.spawn(async move {
+                    if read_only {
+                        reply.set_errno(EPERM as i32);
+                    } else if oor {
+                        reply.set_errno(EINVAL as i32);
+                    } else if let Err(err) = device.write(offset, &buffer, fua).await {
+                        reply.set_errno(default_error_map(&err));
+                    }
+
+                    writer.lock().await.write_all(reply.as_bytes()).await
+                });
+            }
+            nbd::Command::Flush => {
+                let device = Arc::clone(device);
+                let writer = Arc::clone(writer);
+
+                tasks.spawn(async move {
+                    if let Err(err) = device.flush().await {
+                        reply.set_errno(default_error_map(&err));
+                    }
+
+                    writer.lock().await.write_all(reply.as_bytes()).await
+                });
+            }
+            nbd::Command::Trim => {
+                let device = Arc::clone(device);
+                let writer = Arc::clone(writer);
+
+                tasks.spawn(async move {
+                    if read_only {
+                        reply.set_errno(EPERM as i32);
+                    } else if oor {
+                        reply.set_errno(EINVAL as i32);
+                    } else if let Err(err) = device.trim(offset, length).await {
+                        reply.set_errno(default_error_map(&err));
+                    }
+
+                    writer.lock().await.write_all(reply.as_bytes()).await
+                });
+            }
+            nbd::Command::WriteZeroes => {
+                let device = Arc::clone(device);
+                let writer = Arc::clone(writer);
+
+                tasks.spawn(async move {
+                    if read_only {
+                        reply.set_errno(EPERM as i32);
+                    } else if oor {
+                        reply.set_errno(EINVAL as i32);
+                    } else if let Err(err) = device.write_zeroes(offset, length, fua).await {
+                        reply.set_errno(default_error_map(&err));
+                    }
+
+                    writer.lock().await.write_all(reply.as_bytes()).await
+                });
+            }
+            nbd::Command::Disconnect => {
+                device.unmount().await;
+                break; // cancel
+            }
+            nbd::Command::Unknown => unreachable!("NBD driver error: unknown request type"),
+        }
+    }
+
+    // Drain the remaining in-flight tasks so their replies land (and any write
+    // failure is propagated) before the dispatcher returns.
+
+    while let Some(joined) = tasks.join_next().await {
+        joined.expect("request task panicked")?;
+    }
+
+    Ok(())
+}
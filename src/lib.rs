@@ -3,16 +3,20 @@
 #![forbid(missing_docs)]
 
 use crossbeam_utils::thread::scope;
-use nix::errno::Errno::{EIO, EPERM};
+use nix::errno::Errno::{EINVAL, EIO, EPERM};
 use std::fs::{File, OpenOptions};
 use std::io::{Error, Read, Result, Write};
 use std::os::unix::{io::AsRawFd, net::UnixStream};
 use std::path::Path;
+use std::sync::Mutex;
 use std::time::Duration;
 use zerocopy::AsBytes;
 
+mod asynchronous;
 mod nbd;
 
+pub use asynchronous::{mount_async, AsyncBlockDevice};
+
 /// A virtual block device.
 pub trait BlockDevice {
     /// Reads a byte range from the block device.
@@ -29,12 +33,17 @@ pub trait BlockDevice {
 
     /// Writes a byte range to the block device.
     ///
+    /// When `fua` (force unit access) is set the kernel requires this single
+    /// write to be durably committed before the reply is sent, so durability-
+    /// sensitive devices should flush it synchronously rather than relying on a
+    /// later [`flush`](BlockDevice::flush).
+    ///
     /// # Note
     ///
     /// If you return an I/O error not associated with an OS `errno`, vblk
     /// will automatically return an `EIO` error to the caller by default.
-    fn write(&mut self, offset: u64, bytes: &[u8]) -> Result<()> {
-        let _ = (offset, bytes);
+    fn write(&mut self, offset: u64, bytes: &[u8], fua: bool) -> Result<()> {
+        let _ = (offset, bytes, fua);
 
         Err(Error::from_raw_os_error(EPERM as i32))
     }
@@ -69,9 +78,41 @@ pub trait BlockDevice {
         Ok(())
     }
 
+    /// Writes a range of zeroes to the block device.
+    ///
+    /// The default implementation simply writes a zero-filled buffer, but
+    /// devices backed by sparse files or allocation bitmaps can override this
+    /// to punch a hole instead of materializing the zeroes.
+    ///
+    /// The `fua` flag carries the same force-unit-access meaning as for
+    /// [`write`](BlockDevice::write).
+    ///
+    /// # Note
+    ///
+    /// If you return an I/O error not associated with an OS `errno`, vblk
+    /// will automatically return an `EIO` error to the caller by default.
+    ///
+    /// # Warning
+    ///
+    /// Support for this command depends on your Linux kernel version.
+    fn write_zeroes(&mut self, offset: u64, len: u32, fua: bool) -> Result<()> {
+        self.write(offset, &vec![0; len as usize], fua)
+    }
+
     /// Called when the block device is unmounted.
     fn unmount(&mut self) {}
 
+    /// Returns whether the device should be mounted read-only.
+    ///
+    /// When this returns `true` the `NBD_FLAG_READ_ONLY` flag is advertised so
+    /// the kernel rejects writes at the block layer, and vblk additionally
+    /// refuses write, trim and write-zeroes commands with `EPERM` as a defense
+    /// in depth. This is useful for exposing snapshot or archival images where
+    /// accidental writes must be impossible.
+    fn read_only(&self) -> bool {
+        false
+    }
+
     /// Returns the device block size in bytes.
     ///
     /// According to the NBD kernel source code, the block size must currently
@@ -108,25 +149,58 @@ impl Device {
     }
 }
 
+/// The largest request length vblk will accept before rejecting it outright.
+///
+/// A buggy or malicious kernel request could otherwise ask us to allocate an
+/// arbitrarily large buffer, so requests above this cap are answered `EINVAL`.
+const MAX_REQUEST_LEN: u32 = 32 * 1024 * 1024;
+
+/// The default error-to-errno translation used by [`mount`].
+///
+/// OS errors keep their own `errno`; any other error becomes `EIO`. Pass a
+/// custom closure to `mount` to map specific [`io::ErrorKind`](std::io)s to
+/// errnos such as `ENOSPC` or `EROFS`.
+pub fn default_error_map(error: &Error) -> i32 {
+    error.raw_os_error().unwrap_or(EIO as i32)
+}
+
 /// Mounts a block device on an NBD device such as `/dev/nbd0`.
 ///
 /// The callback will be invoked at the start of the mounting process and will
 /// yield a structure which can be used to asynchronously unmount this device.
 ///
+/// The `connections` argument controls how many kernel sockets are attached to
+/// the device: the kernel NBD driver spreads requests across them, so a value
+/// greater than one lets a CPU-bound or I/O-bound device saturate several cores
+/// at once. Each connection is serviced by its own worker thread, all sharing
+/// the device behind a mutex, so the device must be [`Send`]. Pass `1` for the
+/// classic single-queue behaviour.
+///
+/// The `error_map` closure translates errors returned by the device into the
+/// `errno` reported to the kernel; pass [`default_error_map`] for the usual
+/// behaviour, or a custom closure to map specific [`io::ErrorKind`](std::io)s
+/// to errnos such as `ENOSPC` or `EROFS`.
+///
 /// # Safety
 ///
 /// Communicates with the NBD kernel module through ioctls.
 pub unsafe fn mount<P: AsRef<Path>>(
-    device: &mut dyn BlockDevice,
+    device: &mut (dyn BlockDevice + Send),
     path: P,
+    connections: usize,
+    error_map: impl Fn(&Error) -> i32 + Sync,
     callback: impl FnOnce(Device) -> Result<()>,
 ) -> Result<()> {
+    assert!(connections >= 1, "at least one connection is required");
+
     let file = &OpenOptions::new()
         .read(true)
         .write(true)
         .open(path.as_ref())?;
 
     let (block_size, blocks) = (device.block_size(), device.blocks());
+    let read_only = device.read_only();
+    let device_size = blocks * u64::from(block_size);
 
     assert!(block_size.is_power_of_two());
     assert!(block_size >= 512);
@@ -135,20 +209,44 @@ pub unsafe fn mount<P: AsRef<Path>>(
     nbd::set_size_blocks(&file, blocks)?;
     nbd::clear_sock(file)?;
 
-    let (mut userspace_socket, kernel_socket) = UnixStream::pair()?;
+    let mut userspace_sockets = Vec::with_capacity(connections);
+    let mut kernel_sockets = Vec::with_capacity(connections);
+
+    for _ in 0..connections {
+        let (userspace_socket, kernel_socket) = UnixStream::pair()?;
+        userspace_sockets.push(userspace_socket);
+        kernel_sockets.push(kernel_socket);
+    }
+
+    let device = Mutex::new(device);
 
     let result = scope(|scope| -> Result<()> {
         callback(Device {
             file: file.try_clone()?,
         })?;
 
+        let kernel_fds: Vec<_> = kernel_sockets.iter().map(AsRawFd::as_raw_fd).collect();
+
         let thread = scope.spawn(move |_| -> Result<()> {
-            nbd::set_sock(file, kernel_socket.as_raw_fd())?;
+            // Keep the kernel sockets alive for as long as the device is up;
+            // the driver owns them until `do_it` returns on disconnect.
+
+            let _kernel_sockets = kernel_sockets;
+
+            for fd in kernel_fds {
+                nbd::set_sock(file, fd)?;
+            }
 
             // These flags (or even the ability to set flags) are not available
             // in every Linux version; this call is best-effort, ignore errors.
 
-            let _ = nbd::set_flags(file, nbd::SEND_FLUSH | nbd::SEND_TRIM);
+            let mut flags = nbd::SEND_FLUSH | nbd::SEND_FUA | nbd::SEND_TRIM | nbd::SEND_WRITE_ZEROES;
+
+            if read_only {
+                flags |= nbd::READ_ONLY;
+            }
+
+            let _ = nbd::set_flags(file, flags);
 
             nbd::do_it(file)?;
 
@@ -161,71 +259,166 @@ pub unsafe fn mount<P: AsRef<Path>>(
             Ok(())
         });
 
-        let mut request = nbd::Request::default();
-        let mut buffer = Vec::with_capacity(4096);
+        let workers: Vec<_> = userspace_sockets
+            .into_iter()
+            .map(|socket| {
+                let device = &device;
+                let error_map = &error_map;
+                scope.spawn(move |_| {
+                    process_queue(socket, device, read_only, device_size, error_map)
+                })
+            })
+            .collect();
+
+        // Surface the first worker error, but always join every worker so no
+        // queue is left running once the device comes down.
+
+        let mut outcome = Ok(());
+
+        for worker in workers {
+            if let Err(err) = worker.join().unwrap() {
+                if outcome.is_ok() {
+                    outcome = Err(err);
+                }
+            }
+        }
+
+        thread.join().unwrap().and(outcome)
+    });
 
-        loop {
-            let len = userspace_socket.read(&mut request.as_bytes_mut()[0..nbd::REQUEST_LEN])?;
+    if result.is_err() || result.as_ref().unwrap().is_err() {
+        let _ = nbd::disconnect(file); // forced disconnect
+    }
 
-            if len == 0 {
-                break;
-            }
+    result.unwrap()
+}
 
-            assert_eq!(len, nbd::REQUEST_LEN, "NBD driver error: too few bytes");
-            assert!(request.is_magic_valid(), "NBD driver error: invalid magic");
+/// Services a single NBD queue: reads requests off one userspace socket and
+/// dispatches them to the shared device until the socket is closed.
+fn process_queue(
+    mut socket: UnixStream,
+    device: &Mutex<&mut (dyn BlockDevice + Send)>,
+    read_only: bool,
+    device_size: u64,
+    error_map: impl Fn(&Error) -> i32,
+) -> Result<()> {
+    // A request is in bounds if it stays within the cap and the device size;
+    // the subtraction is safe because we check the offset against the size
+    // first, so it can never underflow.
 
-            let mut reply = request.new_reply_for_request();
+    let out_of_range = |offset: u64, len: u32| {
+        len > MAX_REQUEST_LEN || offset > device_size || u64::from(len) > device_size - offset
+    };
 
-            match request.command() {
-                nbd::Command::Read => {
-                    buffer.resize(request.len() as usize, 0);
+    let mut request = nbd::Request::default();
+    let mut buffer = Vec::with_capacity(4096);
 
-                    if let Err(err) = device.read(request.offset(), &mut buffer) {
-                        reply.set_errno(err.raw_os_error().unwrap_or(EIO as i32));
-                    }
+    loop {
+        let len = socket.read(&mut request.as_bytes_mut()[0..nbd::REQUEST_LEN])?;
+
+        if len == 0 {
+            break;
+        }
+
+        assert_eq!(len, nbd::REQUEST_LEN, "NBD driver error: too few bytes");
+        assert!(request.is_magic_valid(), "NBD driver error: invalid magic");
 
-                    userspace_socket.write_all(reply.as_bytes())?;
-                    userspace_socket.write_all(buffer.as_slice())?;
+        let mut reply = request.new_reply_for_request();
+        let fua = request.flags() & nbd::FLAG_FUA != 0;
+
+        match request.command() {
+            nbd::Command::Read => {
+                if out_of_range(request.offset(), request.len()) {
+                    reply.set_errno(EINVAL as i32);
+                    socket.write_all(reply.as_bytes())?;
+                    continue;
                 }
-                nbd::Command::Write => {
-                    buffer.resize(request.len() as usize, 0);
-                    userspace_socket.read_exact(&mut buffer)?;
 
-                    if let Err(err) = device.write(request.offset(), &buffer) {
-                        reply.set_errno(err.raw_os_error().unwrap_or(EIO as i32));
+                buffer.resize(request.len() as usize, 0);
+
+                // Release the device lock before the blocking socket writes so
+                // other queues aren't serialized behind this reply; on error the
+                // buffer contents are undefined, so send only the failure reply
+                // and skip the stale payload, as clients expect.
+
+                let result = device.lock().unwrap().read(request.offset(), &mut buffer);
+
+                match result {
+                    Ok(()) => {
+                        socket.write_all(reply.as_bytes())?;
+                        socket.write_all(buffer.as_slice())?;
                     }
+                    Err(err) => {
+                        reply.set_errno(error_map(&err));
+                        socket.write_all(reply.as_bytes())?;
+                    }
+                }
+            }
+            nbd::Command::Write => {
+                // An oversized payload cannot be drained safely, so reject it
+                // and stop the queue rather than read an unbounded amount.
+
+                if request.len() > MAX_REQUEST_LEN {
+                    reply.set_errno(EINVAL as i32);
+                    socket.write_all(reply.as_bytes())?;
+                    break;
+                }
+
+                buffer.resize(request.len() as usize, 0);
+                socket.read_exact(&mut buffer)?;
 
-                    userspace_socket.write_all(reply.as_bytes())?;
+                if read_only {
+                    reply.set_errno(EPERM as i32);
+                } else if out_of_range(request.offset(), request.len()) {
+                    reply.set_errno(EINVAL as i32);
+                } else if let Err(err) = device.lock().unwrap().write(request.offset(), &buffer, fua)
+                {
+                    reply.set_errno(error_map(&err));
                 }
-                nbd::Command::Flush => {
-                    if let Err(err) = device.flush() {
-                        reply.set_errno(err.raw_os_error().unwrap_or(EIO as i32));
-                    }
 
-                    userspace_socket.write_all(reply.as_bytes())?;
+                socket.write_all(reply.as_bytes())?;
+            }
+            nbd::Command::Flush => {
+                if let Err(err) = device.lock().unwrap().flush() {
+                    reply.set_errno(error_map(&err));
                 }
-                nbd::Command::Trim => {
-                    if let Err(err) = device.trim(request.offset(), request.len()) {
-                        reply.set_errno(err.raw_os_error().unwrap_or(EIO as i32));
-                    }
 
-                    userspace_socket.write_all(reply.as_bytes())?;
+                socket.write_all(reply.as_bytes())?;
+            }
+            nbd::Command::Trim => {
+                if read_only {
+                    reply.set_errno(EPERM as i32);
+                } else if out_of_range(request.offset(), request.len()) {
+                    reply.set_errno(EINVAL as i32);
+                } else if let Err(err) = device.lock().unwrap().trim(request.offset(), request.len())
+                {
+                    reply.set_errno(error_map(&err));
                 }
-                nbd::Command::Disconnect => {
-                    device.unmount();
-                    break; // cancel
+
+                socket.write_all(reply.as_bytes())?;
+            }
+            nbd::Command::WriteZeroes => {
+                if read_only {
+                    reply.set_errno(EPERM as i32);
+                } else if out_of_range(request.offset(), request.len()) {
+                    reply.set_errno(EINVAL as i32);
+                } else if let Err(err) = device
+                    .lock()
+                    .unwrap()
+                    .write_zeroes(request.offset(), request.len(), fua)
+                {
+                    reply.set_errno(error_map(&err));
                 }
-                nbd::Command::Unknown => unreachable!("NBD driver error: unknown request type"),
+
+                socket.write_all(reply.as_bytes())?;
+            }
+            nbd::Command::Disconnect => {
+                device.lock().unwrap().unmount();
+                break; // cancel
             }
+            nbd::Command::Unknown => unreachable!("NBD driver error: unknown request type"),
         }
-
-        drop(userspace_socket);
-        thread.join().unwrap()
-    });
-
-    if result.is_err() || result.as_ref().unwrap().is_err() {
-        let _ = nbd::disconnect(file); // forced disconnect
     }
 
-    result.unwrap()
+    Ok(())
 }
@@ -62,16 +62,24 @@ impl Request {
     }
 
     pub fn command(&self) -> Command {
-        match u32::from_be(self.kind) {
+        // The `kind` field packs the command flags in its top 16 bits and the
+        // command type in the bottom 16, so mask off the flags before matching.
+
+        match u32::from_be(self.kind) & 0xffff {
             CMD_READ => Command::Read,
             CMD_WRITE => Command::Write,
             CMD_DISC => Command::Disconnect,
             CMD_FLUSH => Command::Flush,
             CMD_TRIM => Command::Trim,
+            CMD_WRITE_ZEROES => Command::WriteZeroes,
             _ => Command::Unknown,
         }
     }
 
+    pub fn flags(&self) -> u32 {
+        u32::from_be(self.kind) & 0xffff_0000
+    }
+
     pub fn offset(&self) -> u64 {
         u64::from_be(self.from)
     }
@@ -103,9 +111,15 @@ const CMD_WRITE: u32 = 1;
 const CMD_DISC: u32 = 2;
 const CMD_FLUSH: u32 = 3;
 const CMD_TRIM: u32 = 4;
+const CMD_WRITE_ZEROES: u32 = 6;
 
+pub const READ_ONLY: u64 = 1 << 1;
 pub const SEND_FLUSH: u64 = 1 << 2;
+pub const SEND_FUA: u64 = 1 << 3;
 pub const SEND_TRIM: u64 = 1 << 5;
+pub const SEND_WRITE_ZEROES: u64 = 1 << 6;
+
+pub const FLAG_FUA: u32 = 1 << 16;
 
 #[derive(Clone, Copy, Debug)]
 pub enum Command {
@@ -114,5 +128,6 @@ pub enum Command {
     Disconnect,
     Flush,
     Trim,
+    WriteZeroes,
     Unknown,
 }